@@ -27,9 +27,18 @@
 #[macro_use]
 extern crate log;
 
+extern crate fs2;
+extern crate gethostname;
+
+use fs2::FileExt;
+use gethostname::gethostname;
+
 use std::fs::{self, File, OpenOptions};
 use std::io;
+use std::ops::{Deref, DerefMut};
 use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
 
 /// A wrapper around io::Error to distinguish between the lock already existing and other errors.
 ///
@@ -55,6 +64,12 @@ use std::path::{Path, PathBuf};
 pub enum Error {
     Io(io::Error),
     LockTaken,
+    /// We kept retrying while another holder owned the lock, but the next retry would have taken
+    /// longer than the configured duration.
+    ///
+    /// The `attempts` count is included so callers can report that the lockfile might need manual
+    /// deletion (e.g. if the previous holder crashed without cleaning up).
+    PermanentlyLocked { path: PathBuf, attempts: u32 },
 }
 
 impl Error {
@@ -63,6 +78,14 @@ impl Error {
         match self {
             Error::Io(err) => err,
             Error::LockTaken => io::Error::new(io::ErrorKind::AlreadyExists, "lock already taken"),
+            Error::PermanentlyLocked { path, attempts } => io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!(
+                    r#"lock at "{}" still held after {} attempts"#,
+                    path.display(),
+                    attempts
+                ),
+            ),
         }
     }
 
@@ -74,6 +97,16 @@ impl Error {
     }
 }
 
+/// How [`Lockfile::create_with`] should behave when the lock is already held by another process.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fail {
+    /// Fail immediately with [`Error::LockTaken`] (the behaviour of [`Lockfile::create`]).
+    Immediately,
+    /// Keep retrying with an exponential backoff, giving up with [`Error::PermanentlyLocked`] once
+    /// the next sleep would take the elapsed time past the given duration.
+    AfterDurationWithBackoff(Duration),
+}
+
 /// A lockfile that cleans up after itself.
 ///
 /// Inspired by `TempPath` in `tempfile` crate.
@@ -83,6 +116,12 @@ impl Error {
 pub struct Lockfile {
     handle: Option<File>,
     path: PathBuf,
+    /// Whether the lock is held via an OS advisory lock that must be dropped before unlinking.
+    advisory: bool,
+    /// Whether the file already existed on disk when we acquired an advisory lock on it.
+    was_stale: bool,
+    /// The file this lock stages an update for, if created with [`for_target`](Lockfile::for_target).
+    target: Option<PathBuf>,
 }
 
 impl Lockfile {
@@ -99,6 +138,44 @@ impl Lockfile {
     ///
     /// Will panic if the path doesn't have a parent directory.
     pub fn create(path: impl AsRef<Path>) -> Result<Lockfile, Error> {
+        Lockfile::create_with(path, Fail::Immediately)
+    }
+
+    /// Start building a lockfile with non-default options.
+    ///
+    /// The plain [`create`](Lockfile::create) entry point hard-codes the open options, parent
+    /// directory creation and (on Unix) the file mode. Use the [`LockfileBuilder`] when you need
+    /// to control those, or to stamp identifying metadata into the lock body.
+    #[inline]
+    pub fn builder() -> LockfileBuilder {
+        LockfileBuilder::new()
+    }
+
+    /// The Unix mode bits of the lockfile on disk.
+    ///
+    /// Useful for checking the permissions requested via [`LockfileBuilder::mode`] were applied.
+    #[cfg(unix)]
+    pub fn mode(&self) -> io::Result<u32> {
+        use std::os::unix::fs::PermissionsExt;
+        let handle = self.handle.as_ref().expect("handle already dropped");
+        Ok(handle.metadata()?.permissions().mode())
+    }
+
+    /// Create a lockfile at the given path, controlling what happens if the lock is already held.
+    ///
+    /// With [`Fail::Immediately`] this behaves exactly like [`Lockfile::create`]. With
+    /// [`Fail::AfterDurationWithBackoff`] it keeps retrying while another holder owns the lock,
+    /// sleeping for a growing interval between attempts, and gives up with
+    /// [`Error::PermanentlyLocked`] once the next sleep would push the elapsed time past the
+    /// configured duration.
+    ///
+    /// Only [`io::ErrorKind::AlreadyExists`] is retried; any other io error is returned
+    /// immediately.
+    ///
+    /// # Panics
+    ///
+    /// Will panic if the path doesn't have a parent directory.
+    pub fn create_with(path: impl AsRef<Path>, fail: Fail) -> Result<Lockfile, Error> {
         let path = path.as_ref();
 
         // create parent directory if not exists (match libalpm behaviour)
@@ -112,15 +189,178 @@ impl Lockfile {
         // create lockfile (or get a handle if file already exists)
         let mut lockfile_opts = OpenOptions::new();
         lockfile_opts.create_new(true).read(true).write(true);
-        let lockfile = lockfile_opts.open(path).map_err(Error::from_io)?;
-        debug!(r#"lockfile created at "{}""#, path.display());
+
+        let timeout = match fail {
+            Fail::Immediately => {
+                let lockfile = lockfile_opts.open(path).map_err(Error::from_io)?;
+                debug!(r#"lockfile created at "{}""#, path.display());
+                return Ok(Lockfile {
+                    handle: Some(lockfile),
+                    path: path.to_owned(),
+                    advisory: false,
+                    was_stale: false,
+                    target: None,
+                });
+            }
+            Fail::AfterDurationWithBackoff(timeout) => timeout,
+        };
+
+        // Retry loop with exponential backoff. We keep a 1-based attempt counter and grow the
+        // sleep as `attempt * attempt`, clamped into `[floor, cap]`, with a little jitter to avoid
+        // a thundering herd of waiters all waking at once.
+        const FLOOR_MS: u64 = 10;
+        const CAP_MS: u64 = 1000;
+        let start = Instant::now();
+        let mut attempt: u32 = 1;
+        loop {
+            match lockfile_opts.open(path) {
+                Ok(lockfile) => {
+                    debug!(
+                        r#"lockfile created at "{}" after {} attempt(s)"#,
+                        path.display(),
+                        attempt
+                    );
+                    return Ok(Lockfile {
+                        handle: Some(lockfile),
+                        path: path.to_owned(),
+                        advisory: false,
+                        was_stale: false,
+                        target: None,
+                    });
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                    let mut ms = FLOOR_MS + u64::from(attempt).saturating_mul(u64::from(attempt));
+                    ms = ms.min(CAP_MS);
+                    // cheap, dependency-free jitter of up to ~ms/3, seeded from the clock.
+                    let spread = ms / 3 + 1;
+                    ms += u64::from(start.elapsed().subsec_nanos()) % spread;
+                    let sleep = Duration::from_millis(ms);
+
+                    if start.elapsed() + sleep > timeout {
+                        debug!(
+                            r#"giving up on lock at "{}" after {} attempt(s)"#,
+                            path.display(),
+                            attempt
+                        );
+                        return Err(Error::PermanentlyLocked {
+                            path: path.to_owned(),
+                            attempts: attempt,
+                        });
+                    }
+
+                    thread::sleep(sleep);
+                    attempt += 1;
+                }
+                Err(e) => return Err(Error::from_io(e)),
+            }
+        }
+    }
+
+    /// Acquire the lock using an OS advisory lock (`flock` on Unix, `LockFileEx` on Windows).
+    ///
+    /// Unlike [`create`](Lockfile::create), this opens the file with `create(true)` rather than
+    /// `create_new(true)` and takes an exclusive advisory lock on it. Because the kernel releases
+    /// advisory locks automatically when the owning process dies, a leftover file from a crashed
+    /// or `SIGKILL`ed process does not permanently wedge future acquisitions.
+    ///
+    /// A would-be-blocking lock (another live process holds it) is mapped to [`Error::LockTaken`].
+    ///
+    /// If the file was already present on disk when the lock was taken, a prior run likely crashed
+    /// without cleaning up; [`was_stale`](Lockfile::was_stale) reports this.
+    ///
+    /// # Panics
+    ///
+    /// Will panic if the path doesn't have a parent directory.
+    pub fn acquire(path: impl AsRef<Path>) -> Result<Lockfile, Error> {
+        let path = path.as_ref();
+
+        let dir = path.parent().expect("lockfile path must have a parent");
+        fs::create_dir_all(dir).map_err(Error::Io)?;
+
+        let existed = path.exists();
+
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(path)
+            .map_err(Error::from_io)?;
+
+        match file.try_lock_exclusive() {
+            Ok(()) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Err(Error::LockTaken),
+            Err(e) => return Err(Error::Io(e)),
+        }
+        debug!(r#"advisory lock acquired at "{}""#, path.display());
 
         Ok(Lockfile {
-            handle: Some(lockfile),
+            handle: Some(file),
             path: path.to_owned(),
+            advisory: true,
+            was_stale: existed,
+            target: None,
         })
     }
 
+    /// Create a lockfile that stages an atomic update to `target`.
+    ///
+    /// This mirrors how git writes `ref.lock` and renames it onto `ref`: the lock is created at
+    /// `{target}.lock` (creating parent directories), the caller writes the new contents through
+    /// the [`io::Write`] impl, and [`commit`](Lockfile::commit) then renames the lock over the
+    /// target in a single step so readers never observe a half-written file.
+    ///
+    /// Dropping (or [`release`](Lockfile::release)ing) the lockfile without committing discards
+    /// the staged write and leaves the target untouched.
+    ///
+    /// # Panics
+    ///
+    /// Will panic if the target doesn't have a parent directory.
+    pub fn for_target(target: impl AsRef<Path>) -> Result<Lockfile, Error> {
+        let target = target.as_ref();
+        let mut lock_path = target.as_os_str().to_owned();
+        lock_path.push(".lock");
+        let lock_path = PathBuf::from(lock_path);
+
+        let mut lockfile = Lockfile::create(&lock_path)?;
+        lockfile.target = Some(target.to_owned());
+        Ok(lockfile)
+    }
+
+    /// Atomically move the staged contents onto the target, consuming the lock.
+    ///
+    /// The handle is flushed and `fsync`ed, then the lock path is renamed onto the target in a
+    /// single `rename` so readers of the target only ever see the old or the new file, never a
+    /// partial write.
+    ///
+    /// # Panics
+    ///
+    /// Will panic if the lockfile was not created with [`for_target`](Lockfile::for_target).
+    pub fn commit(mut self) -> io::Result<()> {
+        let handle = self.handle.take().expect("handle already dropped");
+        let target = self
+            .target
+            .take()
+            .expect("commit called on a lockfile not created with `for_target`");
+
+        handle.sync_all()?;
+        drop(handle);
+
+        // `fs::rename` replaces the target atomically on all platforms (on Windows via
+        // `MoveFileExW`/`SetFileInformationByHandle`), so readers only ever see the old or new
+        // file.
+        fs::rename(&self.path, &target)?;
+        debug!(
+            r#"committed lockfile "{}" onto "{}""#,
+            self.path.display(),
+            target.display()
+        );
+
+        // The lock path no longer exists; defuse the destructor so it doesn't try to remove it.
+        self.handle = None;
+        Ok(())
+    }
+
     /// Get the path of the lockfile.
     ///
     /// The impl of `AsRef<Path>` can also be used.
@@ -129,22 +369,152 @@ impl Lockfile {
         self.path.as_path()
     }
 
+    /// Whether the lockfile was already present on disk when an advisory lock was acquired on it.
+    ///
+    /// Only meaningful for locks taken with [`acquire`](Lockfile::acquire); a `true` value
+    /// indicates a previous holder exited without removing the file.
+    #[inline]
+    pub fn was_stale(&self) -> bool {
+        self.was_stale
+    }
+
     /// Close and remove the file, releasing the lock.
     ///
     /// Use this instead of the destructor when you want to see if any errors occured when
     /// removing the file.
     pub fn release(mut self) -> Result<(), io::Error> {
+        let handle = self.handle.take().expect("handle already dropped");
+        if self.advisory {
+            // Drop the advisory lock before the file is unlinked below.
+            FileExt::unlock(&handle)?;
+        }
         // Closes the file.
-        self.handle.take().expect("handle already dropped");
+        drop(handle);
         fs::remove_file(&self.path)?;
         debug!(r#"Removed lockfile at "{}""#, self.path.display());
         Ok(())
     }
 }
 
+/// A configurable builder for [`Lockfile`], returned by [`Lockfile::builder`].
+///
+/// The defaults match [`Lockfile::create`]: parent directories are created, the file is opened
+/// read/write with `create_new`, no mode is forced and no metadata is written.
+#[derive(Debug, Clone)]
+pub struct LockfileBuilder {
+    create_dirs: bool,
+    read: bool,
+    write: bool,
+    write_metadata: bool,
+    #[cfg(unix)]
+    mode: Option<u32>,
+}
+
+impl LockfileBuilder {
+    fn new() -> LockfileBuilder {
+        LockfileBuilder {
+            create_dirs: true,
+            read: true,
+            write: true,
+            write_metadata: false,
+            #[cfg(unix)]
+            mode: None,
+        }
+    }
+
+    /// Set the Unix mode bits the lockfile is created with (via `OpenOptionsExt::mode`).
+    #[cfg(unix)]
+    pub fn mode(mut self, mode: u32) -> LockfileBuilder {
+        self.mode = Some(mode);
+        self
+    }
+
+    /// Whether to create parent directories if they are missing (default `true`).
+    pub fn create_dirs(mut self, create_dirs: bool) -> LockfileBuilder {
+        self.create_dirs = create_dirs;
+        self
+    }
+
+    /// Whether the lockfile is opened for reading (default `true`).
+    pub fn read(mut self, read: bool) -> LockfileBuilder {
+        self.read = read;
+        self
+    }
+
+    /// Whether the lockfile is opened for writing (default `true`).
+    pub fn write(mut self, write: bool) -> LockfileBuilder {
+        self.write = write;
+        self
+    }
+
+    /// Whether to write identifying metadata (the current PID and hostname) into the lock body,
+    /// so other processes can inspect who holds it (default `false`).
+    pub fn write_metadata(mut self, write_metadata: bool) -> LockfileBuilder {
+        self.write_metadata = write_metadata;
+        self
+    }
+
+    /// Create the lockfile at `path` with the configured options.
+    ///
+    /// # Panics
+    ///
+    /// Will panic if the path doesn't have a parent directory and directory creation is enabled.
+    pub fn create(self, path: impl AsRef<Path>) -> Result<Lockfile, Error> {
+        let path = path.as_ref();
+
+        if self.create_dirs {
+            let dir = path.parent().expect("lockfile path must have a parent");
+            fs::create_dir_all(dir).map_err(Error::Io)?;
+            debug!(
+                r#"lockfile parent directories created/found at "{}""#,
+                dir.display()
+            );
+        }
+
+        let mut opts = OpenOptions::new();
+        opts.create_new(true).read(self.read).write(self.write);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            if let Some(mode) = self.mode {
+                opts.mode(mode);
+            }
+        }
+
+        let mut handle = opts.open(path).map_err(Error::from_io)?;
+        debug!(r#"lockfile created at "{}""#, path.display());
+
+        if self.write_metadata {
+            use std::io::Write;
+            // Best-effort identification of the holder; not load-bearing, so don't fail the lock
+            // acquisition if the write itself fails.
+            let line = format!(
+                "{}@{}\n",
+                std::process::id(),
+                gethostname().to_string_lossy()
+            );
+            if let Err(e) = handle.write_all(line.as_bytes()) {
+                warn!(r#"could not write lock metadata to "{}": {}"#, path.display(), e);
+            }
+        }
+
+        Ok(Lockfile {
+            handle: Some(handle),
+            path: path.to_owned(),
+            advisory: false,
+            was_stale: false,
+            target: None,
+        })
+    }
+}
+
 impl Drop for Lockfile {
     fn drop(&mut self) {
         if let Some(handle) = self.handle.take() {
+            if self.advisory {
+                // Drop the advisory lock before the file is unlinked below (see `release`).
+                let _ = FileExt::unlock(&handle);
+            }
             drop(handle);
 
             match fs::remove_file(&self.path) {
@@ -159,6 +529,73 @@ impl Drop for Lockfile {
     }
 }
 
+/// A real data file guarded by a separate sidecar lockfile.
+///
+/// Where [`Lockfile`] conflates "the thing being protected" and "the lock" into a single file,
+/// `LockedFile` keeps them apart: it opens the data file at `path` for reading and writing while
+/// using a `{path}.lock` sidecar purely as the mutual-exclusion token. The sidecar is removed on
+/// drop/release, but the data file persists.
+///
+/// The guard [`Deref`]s to the underlying [`File`], so reads, writes and seeks are forwarded
+/// transparently to the data file.
+#[derive(Debug)]
+pub struct LockedFile {
+    data: File,
+    // Holds the sidecar lock; dropping it removes the sidecar (but not the data file).
+    lock: Lockfile,
+}
+
+impl LockedFile {
+    /// Open `path` for read/write, guarded by a `{path}.lock` sidecar.
+    ///
+    /// Fails with [`Error::LockTaken`] if the sidecar already exists (another holder is live).
+    ///
+    /// # Panics
+    ///
+    /// Will panic if the path doesn't have a parent directory.
+    pub fn open(path: impl AsRef<Path>) -> Result<LockedFile, Error> {
+        let path = path.as_ref();
+
+        let mut lock_path = path.as_os_str().to_owned();
+        lock_path.push(".lock");
+        let lock = Lockfile::create(PathBuf::from(lock_path))?;
+
+        let data = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .map_err(Error::from_io)?;
+        debug!(r#"locked data file opened at "{}""#, path.display());
+
+        Ok(LockedFile { data, lock })
+    }
+
+    /// Remove the sidecar lock, releasing the lock while leaving the data file in place.
+    ///
+    /// Use this instead of the destructor when you want to see if any errors occured when
+    /// removing the sidecar.
+    pub fn release(self) -> Result<(), io::Error> {
+        self.lock.release()
+    }
+}
+
+impl Deref for LockedFile {
+    type Target = File;
+    #[inline]
+    fn deref(&self) -> &File {
+        &self.data
+    }
+}
+
+impl DerefMut for LockedFile {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut File {
+        &mut self.data
+    }
+}
+
 impl AsRef<Path> for Lockfile {
     #[inline]
     fn as_ref(&self) -> &Path {
@@ -213,11 +650,12 @@ mod tests {
     extern crate tempfile;
 
     use self::tempfile::NamedTempFile;
-    use super::{Error, Lockfile};
+    use super::{Error, Fail, LockedFile, Lockfile};
 
     use std::fs;
     use std::io;
     use std::path::PathBuf;
+    use std::time::Duration;
 
     /// create and delete a temp file to get a tmp location.
     fn tmp_path() -> PathBuf {
@@ -246,4 +684,136 @@ mod tests {
             Error::LockTaken
         ));
     }
+
+    #[test]
+    fn backoff_gives_up() {
+        // a held lock that is never released should eventually give up with `PermanentlyLocked`.
+        let path = tmp_path();
+        let _lockfile = Lockfile::create(&path).unwrap();
+        let err = Lockfile::create_with(&path, Fail::AfterDurationWithBackoff(Duration::from_millis(50)))
+            .unwrap_err();
+        assert!(matches!(err, Error::PermanentlyLocked { attempts, .. } if attempts >= 1));
+    }
+
+    #[test]
+    fn advisory_lock_twice() {
+        // a second advisory acquisition of a held lock is `LockTaken` while the first holder is
+        // live.
+        let path = tmp_path();
+        let first = Lockfile::acquire(&path).unwrap();
+        // `tmp_path` hands back a fresh location, so the first acquisition is not stale.
+        assert!(!first.was_stale());
+        assert!(matches!(Lockfile::acquire(&path).unwrap_err(), Error::LockTaken));
+        first.release().unwrap();
+    }
+
+    #[test]
+    fn advisory_tolerates_stale_lockfile() {
+        use super::fs2::FileExt;
+
+        // Simulate a process that acquired the lock and then crashed: the file is left on disk
+        // but the kernel releases the flock when the fd is closed, without any unlink.
+        let path = tmp_path();
+        {
+            let crashed = fs::OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .read(true)
+                .write(true)
+                .open(&path)
+                .unwrap();
+            crashed.try_lock_exclusive().unwrap();
+            // drop without unlinking — mimics a SIGKILL
+        }
+
+        // A fresh acquisition succeeds and reports that it found a leftover file.
+        let lockfile = Lockfile::acquire(&path).unwrap();
+        assert!(lockfile.was_stale());
+        lockfile.release().unwrap();
+    }
+
+    #[test]
+    fn commit_replaces_target() {
+        use std::io::Write;
+
+        let target = tmp_path();
+        fs::write(&target, b"old").unwrap();
+
+        let mut lock = Lockfile::for_target(&target).unwrap();
+        lock.write_all(b"new").unwrap();
+        lock.commit().unwrap();
+
+        assert_eq!(fs::read(&target).unwrap(), b"new");
+        fs::remove_file(&target).unwrap();
+    }
+
+    #[test]
+    fn abort_leaves_target_untouched() {
+        let target = tmp_path();
+        fs::write(&target, b"old").unwrap();
+
+        {
+            let _lock = Lockfile::for_target(&target).unwrap();
+            // dropped without commit
+        }
+
+        assert_eq!(fs::read(&target).unwrap(), b"old");
+        // the sidecar lock was cleaned up on drop.
+        let mut lock_path = target.clone().into_os_string();
+        lock_path.push(".lock");
+        assert_eq!(
+            fs::metadata(PathBuf::from(lock_path)).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+        fs::remove_file(&target).unwrap();
+    }
+
+    #[test]
+    fn locked_file_guards_data() {
+        use std::io::{Read, Seek, SeekFrom, Write};
+
+        let path = tmp_path();
+
+        let mut file = LockedFile::open(&path).unwrap();
+        file.write_all(b"payload").unwrap();
+
+        // the sidecar blocks a second holder while the first is live.
+        assert!(matches!(LockedFile::open(&path).unwrap_err(), Error::LockTaken));
+
+        file.seek(SeekFrom::Start(0)).unwrap();
+        let mut buf = String::new();
+        file.read_to_string(&mut buf).unwrap();
+        assert_eq!(buf, "payload");
+
+        file.release().unwrap();
+
+        // the data file survives, the sidecar does not.
+        assert_eq!(fs::read(&path).unwrap(), b"payload");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn builder_sets_mode() {
+        let path = tmp_path();
+        let lockfile = Lockfile::builder().mode(0o600).create(&path).unwrap();
+        // only the permission bits are significant.
+        assert_eq!(lockfile.mode().unwrap() & 0o777, 0o600);
+        lockfile.release().unwrap();
+    }
+
+    #[test]
+    fn builder_writes_metadata() {
+        use std::io::{Read, Seek, SeekFrom};
+
+        let path = tmp_path();
+        let lockfile = Lockfile::builder().write_metadata(true).create(&path).unwrap();
+
+        let mut body = String::new();
+        let mut reader = &lockfile;
+        reader.seek(SeekFrom::Start(0)).unwrap();
+        reader.read_to_string(&mut body).unwrap();
+        assert!(body.contains(&format!("{}@", std::process::id())));
+        lockfile.release().unwrap();
+    }
 }